@@ -1,26 +1,111 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{self, ErrorKind};
+use std::net::TcpListener as StdTcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{cell::RefCell, net::SocketAddr, usize};
 
 use crypto::{digest::Digest, sha2::Sha256};
 use mio::{net::TcpListener, unix::UnixReady, Events, Poll, PollOpt, Ready, Token};
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
 use pump::Pump;
 use slab::Slab;
 
+// Per-shard connection ceiling. With one shard per core the effective limit
+// scales with the shard count instead of being a single global cap.
 const MAX_PUMPS: usize = 2048;
 const ROOT_TOKEN: Token = Token(<usize>::max_value() - 1);
 
-pub struct Server {
+/// A strictly-increasing connection identifier assigned when a connection is
+/// registered (at `accept`, and for each upstream peer pump) and never reused. It is decoupled from the slab index so a recycled slot can't
+/// inherit a previous connection's links, zombie entry, or deadline; the
+/// `slots` map translates it back to the live slab index on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct ConnId(u64);
+
+impl ConnId {
+  /// The id travels through mio as the registration `Token`, keeping events,
+  /// `links`, and `zombie` all keyed on the same never-reused value.
+  fn token(self) -> Token {
+    Token(self.0 as usize)
+  }
+}
+
+// Idle windows: a connection that has not made progress within its window is
+// reaped. Handshaking links get a tight window until their peer pump exists;
+// once a pair is linked we fall back to a longer keepalive window, mirroring
+// the PING_PERIOD / read-time-limit split used by comparable mio proxies.
+const HANDSHAKE_IDLE: Duration = Duration::from_secs(10);
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+
+// Relay backpressure: when a peer's outbound buffer grows past the high-water
+// mark we stop reading the other direction, and resume only once it has
+// drained below the low-water mark. Sized against the 10 MB packet/read limit
+// so a stalled link self-throttles long before it can OOM the process.
+const BUF_HIGH_WATER: usize = 4 * 1024 * 1024;
+const BUF_LOW_WATER: usize = 1 * 1024 * 1024;
+
+/// Configuration for the optional encrypted proxy-to-proxy tunnel. When
+/// present, the upstream peer pump opened in `drain` is not a plain socket to
+/// the destination but a Noise-style `XX` tunnel (x25519 + ChaCha20-Poly1305)
+/// to a sibling proxy: the two ends exchange ephemeral then static public keys
+/// authenticated under the transcript hash, derive per-direction keys, and
+/// seal/open every relayed chunk from then on.
+#[derive(Clone)]
+pub struct Tunnel {
+  /// This proxy's static x25519 secret key.
+  pub static_secret: [u8; 32],
+  /// The configured sibling proxy's static x25519 public key.
+  pub remote_public: [u8; 32],
+}
+
+/// A single event-loop shard. Each shard owns an independent `Poll`, `Slab`,
+/// and link/zombie state, and binds the listen address with `SO_REUSEPORT` so
+/// the kernel load-balances accepted connections across shards. A client and
+/// its upstream peer pump are always created by the same worker, so a linked
+/// pair never spans shards and no cross-shard locking is needed.
+struct Worker {
   sock: TcpListener,
   poll: Poll,
   secret: Vec<u8>,
+  capacity: usize,
+  // When set, upstream peer pumps are opened as encrypted tunnels to a sibling
+  // proxy instead of plain sockets to the destination.
+  tunnel: Option<Tunnel>,
   pumps: Slab<RefCell<Pump>>,
   zombie: HashSet<Token>,
   links: HashMap<Token, Token>,
+  // Live `ConnId` token -> slab index. A token missing from here is a
+  // connection that has already been torn down, so any deferred action keyed
+  // on it must be skipped rather than applied to whatever reused the slot.
+  slots: HashMap<Token, usize>,
+  next_id: u64,
+  // Tokens whose readable interest is currently withheld because their peer's
+  // outbound buffer is over the high-water mark.
+  paused: HashSet<Token>,
+  // Tunnel peer token -> owner token, for peers still completing their XX
+  // handshake. The pair is kept out of `links` (so neither end fans payload)
+  // until the tunnel reports ready.
+  pending: HashMap<Token, Token>,
+  // Expiry wheel: the ordered map gives us the nearest deadline to poll on,
+  // while `deadline_of` lets us invalidate a token's entry when it is re-armed
+  // or torn down. Keys carry the never-reused token, not a slab index.
+  deadlines: BTreeMap<(Instant, usize), Token>,
+  deadline_of: HashMap<Token, Instant>,
+}
+
+/// The proxy front-end: derives the shared secret and fans the listen address
+/// out to `shards` worker event loops (at least one).
+pub struct Server {
+  addr: SocketAddr,
+  secret: Vec<u8>,
+  shards: usize,
+  tunnel: Option<Tunnel>,
 }
 
 impl Server {
-  pub fn new(addr: SocketAddr, seed: &str) -> Server {
+  pub fn new(addr: SocketAddr, seed: &str, shards: usize, tunnel: Option<Tunnel>) -> Server {
     let mut sha = Sha256::new();
     let mut secret = vec![0u8; sha.output_bytes()];
 
@@ -29,12 +114,10 @@ impl Server {
     secret.truncate(16);
 
     Server {
+      addr,
       secret,
-      zombie: HashSet::new(),
-      sock: TcpListener::bind(&addr).expect("Failed to bind"),
-      poll: Poll::new().expect("Failed to create Poll"),
-      pumps: Slab::with_capacity(MAX_PUMPS),
-      links: HashMap::new(),
+      shards: shards.max(1),
+      tunnel,
     }
   }
 
@@ -43,8 +126,121 @@ impl Server {
     secret.join("")
   }
 
+  /// Spawn one worker thread per shard and block until they exit. Each worker
+  /// binds its own `SO_REUSEPORT` listener, so the kernel spreads connections
+  /// across the shards without any shared accept path.
   pub fn run(&mut self) -> io::Result<()> {
-    info!("Starting proxy");
+    info!("starting proxy with {} shard(s)", self.shards);
+
+    let handles: Vec<_> = (0..self.shards)
+      .map(|shard| {
+        let addr = self.addr;
+        let secret = self.secret.clone();
+        let tunnel = self.tunnel.clone();
+        thread::Builder::new()
+          .name(format!("worker-{}", shard))
+          .spawn(move || {
+            let mut worker =
+              Worker::new(addr, secret, MAX_PUMPS, tunnel).expect("worker bind failed");
+            if let Err(e) = worker.run() {
+              error!("worker {} exited: {}", shard, e);
+            }
+          })
+          .expect("failed to spawn worker")
+      })
+      .collect();
+
+    for handle in handles {
+      let _ = handle.join();
+    }
+
+    Ok(())
+  }
+}
+
+impl Worker {
+  fn new(
+    addr: SocketAddr,
+    secret: Vec<u8>,
+    capacity: usize,
+    tunnel: Option<Tunnel>,
+  ) -> io::Result<Worker> {
+    Ok(Worker {
+      secret,
+      capacity,
+      tunnel,
+      zombie: HashSet::new(),
+      sock: bind_reuseport(&addr)?,
+      poll: Poll::new()?,
+      pumps: Slab::with_capacity(capacity),
+      links: HashMap::new(),
+      slots: HashMap::new(),
+      next_id: 0,
+      paused: HashSet::new(),
+      pending: HashMap::new(),
+      deadlines: BTreeMap::new(),
+      deadline_of: HashMap::new(),
+    })
+  }
+
+  /// Hand out the next connection id. The `u64` counter is never reset, so two
+  /// connections can never share an id even as the slab recycles slots.
+  fn next_conn_id(&mut self) -> ConnId {
+    let id = ConnId(self.next_id);
+    self.next_id += 1;
+    id
+  }
+
+  /// (Re)arm a token's idle deadline `window` into the future, dropping any
+  /// deadline it held before so a slot never carries two live entries.
+  fn touch(&mut self, token: Token, window: Duration) {
+    self.invalidate(token);
+    let at = Instant::now() + window;
+    self.deadlines.insert((at, token.0), token);
+    self.deadline_of.insert(token, at);
+  }
+
+  /// Forget a token's deadline. Called when a deadline is re-armed and when a
+  /// connection is torn down, so the reaper never fires for a retired token.
+  fn invalidate(&mut self, token: Token) {
+    if let Some(at) = self.deadline_of.remove(&token) {
+      self.deadlines.remove(&(at, token.0));
+    }
+  }
+
+  /// Duration until the nearest deadline, or `None` to block indefinitely.
+  fn next_timeout(&self) -> Option<Duration> {
+    self
+      .deadlines
+      .keys()
+      .next()
+      .map(|(at, _)| at.saturating_duration_since(Instant::now()))
+  }
+
+  /// Collect every token whose deadline has passed into `stale`. A popped entry
+  /// is only honoured when it still matches `deadline_of`; `touch` and
+  /// `invalidate` already remove superseded keys, so this is a consistency guard.
+  fn reap(&mut self, stale: &mut HashSet<Token>) {
+    let now = Instant::now();
+    let expired: Vec<(Instant, usize)> = self
+      .deadlines
+      .range(..=(now, usize::max_value()))
+      .map(|(key, _)| *key)
+      .collect();
+
+    for key in expired {
+      let token = match self.deadlines.remove(&key) {
+        Some(token) => token,
+        None => continue,
+      };
+      if self.deadline_of.get(&token) == Some(&key.0) {
+        self.deadline_of.remove(&token);
+        stale.insert(token);
+      }
+    }
+  }
+
+  fn run(&mut self) -> io::Result<()> {
     self
       .poll
       .register(&self.sock, ROOT_TOKEN, Ready::readable(), PollOpt::edge())?;
@@ -52,14 +248,15 @@ impl Server {
     let mut events = Events::with_capacity(1024);
 
     loop {
-      self.poll.poll(&mut events, None)?;
+      let timeout = self.next_timeout();
+      self.poll.poll(&mut events, timeout)?;
       self.dispatch(&events)?;
     }
   }
 
   fn accept(&mut self) -> io::Result<()> {
-    if self.pumps.len() > MAX_PUMPS {
-      warn!("max connection limit({}) exceeded", MAX_PUMPS / 2);
+    if self.pumps.len() > self.capacity {
+      warn!("max connection limit({}) exceeded", self.capacity / 2);
       return Ok(());
     }
 
@@ -71,11 +268,15 @@ impl Server {
       }
     };
 
-    let pump = Pump::new(sock, &self.secret);
+    // The client pump carries the tunnel config so the upstream peer it opens
+    // in `drain` can be an encrypted sibling link rather than a plain socket.
+    let pump = Pump::new(sock, &self.secret, self.tunnel.clone());
     let idx = self.pumps.insert(RefCell::new(pump));
-    let pump = self.pumps.get(idx).unwrap().borrow();
 
-    let token = Token(idx);
+    let token = self.next_conn_id().token();
+    self.slots.insert(token, idx);
+
+    let pump = self.pumps.get(idx).unwrap().borrow();
 
     self.poll.register(
       pump.sock(),
@@ -90,12 +291,16 @@ impl Server {
       pump.sock().peer_addr()?
     );
 
+    drop(pump);
+    self.touch(token, HANDSHAKE_IDLE);
+
     Ok(())
   }
 
   fn dispatch(&mut self, events: &Events) -> io::Result<()> {
     let mut stale = HashSet::new();
     let mut seen = HashSet::new();
+    let mut progressed = HashSet::new();
     let mut new_peers = HashMap::new();
 
     for event in events {
@@ -111,45 +316,105 @@ impl Server {
 
       let readiness = UnixReady::from(event.readiness());
 
-      let mut pump = {
-        let pump = &self.pumps.get(token.0);
-        if pump.is_none() {
-          warn!("slab inconsistency");
+      let idx = match self.slots.get(&token) {
+        Some(idx) => *idx,
+        None => {
+          // The connection was torn down before this event drained; its id is
+          // retired, so skip rather than touch the slot that replaced it.
+          warn!("stale token: {:?}", token);
           continue;
         }
-        pump.unwrap().borrow_mut()
       };
 
-      if readiness.is_readable() {
-        loop {
-          match pump.drain() {
-            Ok(peer) => match peer {
-              Some(peer_pump) => {
-                new_peers.insert(token, peer_pump);
+      let mut pump = self.pumps.get(idx).unwrap().borrow_mut();
+
+      // Advance the XX state machine at most once per tick even when the event
+      // carries both readable and writable readiness.
+      let mut advanced_handshake = false;
+
+      if readiness.is_readable() && pump.handshaking() {
+        // Route bytes through the XX handshake; hold off all relaying (and the
+        // backpressure bookkeeping below) until keys are established. A tag or
+        // transcript failure is fatal for the link.
+        if let Err(e) = pump.advance_handshake() {
+          warn!("handshake failed: {:?}: {}", token, e);
+          stale.insert(token);
+        }
+        advanced_handshake = true;
+        progressed.insert(token);
+      } else if readiness.is_readable() {
+        // Hold off reading while the peer we fan out to is already backed up;
+        // draining more here would only grow its unbounded outbound buffer.
+        let peer_flooded = self
+          .links
+          .get(&token)
+          .and_then(|peer_token| self.slots.get(peer_token))
+          .map(|idx| self.pumps.get(*idx).unwrap().borrow().pending() >= BUF_HIGH_WATER)
+          .unwrap_or(false);
+
+        if peer_flooded {
+          self.paused.insert(token);
+        } else {
+          self.paused.remove(&token);
+
+          loop {
+            match pump.drain() {
+              Ok(peer) => match peer {
+                Some(peer_pump) => {
+                  new_peers.insert(token, peer_pump);
+                }
+                _ => {}
+              },
+              Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                break;
+              }
+              Err(e) => {
+                warn!("drain failed: {:?}: {}", token, e);
+                stale.insert(token);
+                break;
               }
-              _ => {}
-            },
+            }
+          }
+
+          if let Some(peer_token) = self.links.get(&token) {
+            self.fan_out(&mut pump, peer_token)?;
+          }
+        }
+
+        progressed.insert(token);
+      }
+
+      if readiness.is_writable() && pump.handshaking() {
+        // Flush any pending handshake message and advance the state machine;
+        // no payload is fanned in until the tunnel is up. Skip the advance if
+        // the readable branch already stepped it this tick.
+        if !advanced_handshake {
+          if let Err(e) = pump.advance_handshake() {
+            warn!("handshake failed: {:?}: {}", token, e);
+            stale.insert(token);
+          }
+        }
+        loop {
+          match pump.flush() {
+            Ok(_) => {}
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
               break;
             }
             Err(e) => {
-              warn!("drain failed: {:?}: {}", token, e);
+              warn!("flush failed: {:?}: {}", token, e);
               stale.insert(token);
               break;
             }
           }
         }
-
-        if let Some(peer_token) = self.links.get(&token) {
-          self.fan_out(&mut pump, peer_token)?;
-        }
-      }
-
-      if readiness.is_writable() {
+        progressed.insert(token);
+      } else if readiness.is_writable() {
         if let Some(peer_token) = self.links.get(&token) {
           self.fan_in(&mut pump, peer_token)?;
         }
 
+        progressed.insert(token);
+
         loop {
           match pump.flush() {
             Ok(_) => {}
@@ -163,28 +428,74 @@ impl Server {
             }
           }
         }
+
+        // Our outbound buffer has drained: let the paused feeder read again.
+        if pump.pending() < BUF_LOW_WATER {
+          if let Some(feeder) = self.links.get(&token).copied() {
+            if self.paused.remove(&feeder) {
+              if let Some(idx) = self.slots.get(&feeder) {
+                let feeder_pump = self.pumps.get(*idx).unwrap().borrow();
+                self.poll.reregister(
+                  feeder_pump.sock(),
+                  feeder,
+                  feeder_pump.interest(),
+                  PollOpt::edge() | PollOpt::oneshot(),
+                )?;
+              }
+            }
+          }
+        }
+      }
+
+      // A tunnel peer that has finished its handshake can now be linked to its
+      // owner; only here is it safe to relay payload in either direction.
+      if !pump.handshaking() {
+        if let Some(owner) = self.pending.remove(&token) {
+          if self.slots.contains_key(&owner) {
+            self.links.insert(token, owner);
+            self.links.insert(owner, token);
+            self.touch(token, KEEPALIVE_IDLE);
+            self.touch(owner, KEEPALIVE_IDLE);
+          } else {
+            // Owner went away mid-handshake; the tunnel has nowhere to go.
+            stale.insert(token);
+          }
+        }
       }
 
       if readiness.is_hup() || readiness.is_error() {
         stale.insert(token);
       } else {
+        let mut interest = pump.interest();
+        if self.paused.contains(&token) {
+          // Withhold readable interest so edge-triggered mio stops waking us to
+          // read into a peer that can't keep up.
+          interest.remove(Ready::readable());
+        }
         self.poll.reregister(
           pump.sock(),
           token,
-          pump.interest(),
+          interest,
           PollOpt::edge() | PollOpt::oneshot(),
         )?;
       }
     }
 
     for (owner, pump) in new_peers {
+      // Skip peers whose owner vanished between `drain` and here; its id is
+      // gone, so the link would dangle.
+      if !self.slots.contains_key(&owner) {
+        continue;
+      }
+
       let idx = self.pumps.insert(RefCell::new(pump));
-      let pump = self.pumps.get(idx).unwrap().borrow();
 
-      let token = Token(idx);
+      let token = self.next_conn_id().token();
+      self.slots.insert(token, idx);
+
+      let pump = self.pumps.get(idx).unwrap().borrow();
 
-      self.links.insert(token, owner);
-      self.links.insert(owner, token);
+      let handshaking = pump.handshaking();
 
       self.poll.register(
         pump.sock(),
@@ -192,8 +503,50 @@ impl Server {
         pump.interest(),
         PollOpt::edge() | PollOpt::oneshot(),
       )?;
+
+      drop(pump);
+
+      if handshaking {
+        // Encrypted tunnel: hold the link back until the XX handshake finishes
+        // so no plaintext is fanned across an unkeyed peer. Keep a tight idle
+        // window while the handshake is in flight.
+        self.pending.insert(token, owner);
+        self.touch(token, HANDSHAKE_IDLE);
+      } else {
+        // Plain peer: the pair is established, both ends switch to keepalive.
+        self.links.insert(token, owner);
+        self.links.insert(owner, token);
+        self.touch(token, KEEPALIVE_IDLE);
+        self.touch(owner, KEEPALIVE_IDLE);
+      }
+    }
+
+    for token in &progressed {
+      if self.deadline_of.contains_key(token) {
+        // Only linked pairs earn the keepalive window; a connection still
+        // waiting on its peer (or its tunnel handshake) stays on the tight one.
+        let window = if self.links.contains_key(token) {
+          KEEPALIVE_IDLE
+        } else {
+          HANDSHAKE_IDLE
+        };
+        self.touch(*token, window);
+      }
+    }
+
+    // A paused feeder is throttled on purpose, not idle: it gets no events
+    // while readable interest is withheld, so refresh its deadline on every
+    // wake (dispatch also runs on timeouts) to keep the reaper from tearing
+    // down a still-live, well-behaved link.
+    let paused: Vec<Token> = self.paused.iter().cloned().collect();
+    for token in paused {
+      if self.deadline_of.contains_key(&token) {
+        self.touch(token, KEEPALIVE_IDLE);
+      }
     }
 
+    self.reap(&mut stale);
+
     self.drop_zombies()?;
 
     for token in stale {
@@ -219,11 +572,21 @@ impl Server {
   }
 
   fn drop_pump(&mut self, token: Token) -> io::Result<()> {
-    let pump = self.pumps.remove(token.0);
+    // The id may already be retired (e.g. a zombie whose slot was reclaimed);
+    // verify it still maps to a live slot before touching the slab.
+    let idx = match self.slots.remove(&token) {
+      Some(idx) => idx,
+      None => return Ok(()),
+    };
+
+    let pump = self.pumps.remove(idx);
     let pump = pump.borrow_mut();
 
     info!("dropping pump: {:?}", token);
     self.poll.deregister(pump.sock())?;
+    self.invalidate(token);
+    self.paused.remove(&token);
+    self.pending.remove(&token);
 
     match self.links.remove(&token) {
       Some(peer_token) => {
@@ -243,7 +606,10 @@ impl Server {
       return Ok(false);
     }
 
-    let peer = self.pumps.get(peer_token.0).unwrap();
+    let peer = match self.slots.get(peer_token) {
+      Some(idx) => self.pumps.get(*idx).unwrap(),
+      None => return Ok(false),
+    };
     let mut peer = peer.borrow_mut();
     peer.push(&buf);
 
@@ -260,7 +626,10 @@ impl Server {
   fn fan_in(&self, pump: &mut Pump, peer_token: &Token) -> io::Result<bool> {
     trace!("fan in from {:?}", peer_token);
 
-    let peer = self.pumps.get(peer_token.0).unwrap();
+    let peer = match self.slots.get(peer_token) {
+      Some(idx) => self.pumps.get(*idx).unwrap(),
+      None => return Ok(false),
+    };
 
     let mut peer = peer.borrow_mut();
     let buf = peer.pull();
@@ -280,3 +649,19 @@ impl Server {
     Ok(true)
   }
 }
+
+/// Bind `addr` with `SO_REUSEADDR | SO_REUSEPORT` so every shard can hold the
+/// same listen address and let the kernel balance accepts between them.
+fn bind_reuseport(addr: &SocketAddr) -> io::Result<TcpListener> {
+  let builder = match *addr {
+    SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+    SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+  };
+  builder.reuse_address(true)?;
+  builder.reuse_port(true)?;
+
+  let listener: StdTcpListener = builder.bind(addr)?.listen(1024)?;
+  listener.set_nonblocking(true)?;
+
+  TcpListener::from_std(listener)
+}